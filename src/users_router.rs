@@ -4,7 +4,7 @@
 
 use super::users_db::{UserBuilder, UserBuilderError, UsersDb};
 
-use iron::{AfterMiddleware, headers, status};
+use iron::{AfterMiddleware, BeforeMiddleware, headers, status};
 use iron::method::Method;
 use iron::method::Method::*;
 use iron::prelude::*;
@@ -12,72 +12,276 @@ use router::Router;
 use rustc_serialize::json;
 use unicase::UniCase;
 
+use std::collections::HashSet;
 use std::error::Error;
 use std::fmt::{self, Debug};
 use std::io::Read;
 
-type Endpoint = (Method, &'static[&'static str]);
+// A CORS policy scoped to a single endpoint: the extra headers it's
+// willing to advertise and whether it accepts credentialed requests.
+// Distinct from the cross-cutting `CORS` config (allowed origins, preflight
+// cache duration), which applies the same way to every endpoint.
+struct EndpointPolicy {
+    allowed_headers: &'static [&'static str],
+    allow_credentials: bool,
+}
+
+impl EndpointPolicy {
+    // The historical behaviour: accept/content-type headers, no
+    // credentials.
+    const DEFAULT: EndpointPolicy = EndpointPolicy {
+        allowed_headers: &["accept", "content-type"],
+        allow_credentials: false,
+    };
+
+    // For broadly-readable, side-effect-free endpoints that don't need to
+    // see request bodies.
+    const READ_ONLY: EndpointPolicy = EndpointPolicy {
+        allowed_headers: &["accept"],
+        allow_credentials: false,
+    };
+}
+
+struct Endpoint {
+    path: &'static [&'static str],
+    methods: &'static [Method],
+    policy: EndpointPolicy,
+}
+
+// The set of origins a CORS-enabled endpoint will accept. `Any` preserves
+// the historical wildcard behaviour (any origin is accepted), while `List`
+// restricts matches to a configured whitelist.
+#[derive(Clone)]
+enum AllowedOrigins {
+    Any,
+    List(HashSet<String>),
+}
+
+#[derive(Clone)]
+struct CORS {
+    allowed_origins: AllowedOrigins,
+    max_age: Option<u32>,
+}
+
+// Builds a `CORS` middleware. Defaults to the historical behaviour (any
+// origin, no preflight caching) so existing callers keep working
+// unchanged; call the setters to narrow the policy. Per-endpoint headers
+// and credential handling live on `CORS::ENDPOINTS` instead, since they
+// vary endpoint to endpoint.
+struct CorsBuilder {
+    allowed_origins: AllowedOrigins,
+    max_age: Option<u32>,
+}
+
+impl CorsBuilder {
+    pub fn new() -> CorsBuilder {
+        CorsBuilder {
+            allowed_origins: AllowedOrigins::Any,
+            max_age: None,
+        }
+    }
+
+    pub fn allowed_origins(mut self, origins: AllowedOrigins) -> CorsBuilder {
+        self.allowed_origins = origins;
+        self
+    }
+
+    // Sets `Access-Control-Max-Age`, in seconds, so browsers cache the
+    // preflight result instead of issuing one before every request.
+    pub fn max_age(mut self, seconds: u32) -> CorsBuilder {
+        self.max_age = Some(seconds);
+        self
+    }
 
-struct CORS;
+    pub fn build(self) -> CORS {
+        CorsBuilder::check_credentialed_endpoints(&self.allowed_origins,
+                                                   CORS::ENDPOINTS);
+
+        CORS {
+            allowed_origins: self.allowed_origins,
+            max_age: self.max_age,
+        }
+    }
+
+    // The Fetch spec forbids combining credentials with a wildcard origin;
+    // browsers silently drop such a response, so refuse to build a
+    // configuration that would produce one.
+    fn check_credentialed_endpoints(allowed_origins: &AllowedOrigins,
+        endpoints: &[Endpoint]) {
+        if let AllowedOrigins::Any = *allowed_origins {
+            assert!(
+                !endpoints.iter().any(|e| e.policy.allow_credentials),
+                "CORS: an endpoint policy allows credentials, which cannot \
+                 be combined with an \"any origin\" policy");
+        }
+    }
+}
 
 impl CORS {
     // Only endpoints listed here will allow CORS.
     // Endpoints containing a variable path part can use '*' like in:
     // &["users", "*"]
-    pub const ENDPOINTS: &'static[Endpoint] = &[
-        (Method::Post,      &["invitations"]),
-        (Method::Get,       &["invitations"]),
-        (Method::Delete,    &["invitations"]),
-        (Method::Post,      &["users"]),
-        (Method::Get,       &["users"]),
-        (Method::Put,       &["users", "*"]),
-        (Method::Post,      &["users", "*"]),
-        (Method::Post,      &["recoveries", "*"]),
-        (Method::Get,       &["recoveries", "*", "*"]),
-        (Method::Get,       &["permissions"]),
-        (Method::Get,       &["permissions", "*"]),
-        (Method::Get,       &["permissions", "*", "*"]),
-        (Method::Get,       &["permissions", "_", "*"]),
-        (Method::Put,       &["permissions", "*", "*"]),
+    pub const ENDPOINTS: &'static [Endpoint] = &[
+        Endpoint { path: &["invitations"],
+                   methods: &[Method::Post, Method::Get, Method::Delete],
+                   policy: EndpointPolicy::DEFAULT },
+        Endpoint { path: &["users"],
+                   methods: &[Method::Post, Method::Get],
+                   policy: EndpointPolicy::DEFAULT },
+        Endpoint { path: &["users", "*"],
+                   methods: &[Method::Put, Method::Post],
+                   policy: EndpointPolicy::DEFAULT },
+        Endpoint { path: &["recoveries", "*"],
+                   methods: &[Method::Post],
+                   policy: EndpointPolicy::DEFAULT },
+        Endpoint { path: &["recoveries", "*", "*"],
+                   methods: &[Method::Get],
+                   policy: EndpointPolicy::DEFAULT },
+        Endpoint { path: &["permissions"],
+                   methods: &[Method::Get],
+                   policy: EndpointPolicy::READ_ONLY },
+        Endpoint { path: &["permissions", "*"],
+                   methods: &[Method::Get],
+                   policy: EndpointPolicy::READ_ONLY },
+        Endpoint { path: &["permissions", "*", "*"],
+                   methods: &[Method::Get, Method::Put],
+                   policy: EndpointPolicy::DEFAULT },
+        Endpoint { path: &["permissions", "_", "*"],
+                   methods: &[Method::Get],
+                   policy: EndpointPolicy::READ_ONLY },
     ];
-}
 
-impl AfterMiddleware for CORS {
-    fn after(&self, req: &mut Request, mut res: Response)
-        -> IronResult<Response> {
+    pub fn new() -> CORS {
+        CorsBuilder::new().build()
+    }
 
-        let mut is_cors_endpoint = false;
-        for endpoint in CORS::ENDPOINTS {
-            let (ref method, path) = *endpoint;
-            if req.method != *method {
-                continue;
-            }
-            if path.len() != req.url.path.len() {
-                continue;
-            }
-            for (i, path) in path.iter().enumerate() {
-                is_cors_endpoint = false;
-                if req.url.path[i] != path.to_string() &&
-                   "*".to_string() != path.to_string() {
-                    break;
+    // Returns the exact origin to echo back in `Access-Control-Allow-Origin`
+    // if `origin` is permitted by this policy, or `None` otherwise.
+    fn allow_origin(&self, origin: Option<&headers::Origin>) -> Option<String> {
+        let origin = match origin {
+            Some(origin) => origin.to_string(),
+            None => return None,
+        };
+
+        match self.allowed_origins {
+            AllowedOrigins::Any => Some(origin),
+            AllowedOrigins::List(ref allowed) => {
+                if allowed.contains(&origin) {
+                    Some(origin)
+                } else {
+                    None
                 }
-                is_cors_endpoint = true;
-            }
-            if is_cors_endpoint {
-                break;
             }
         }
+    }
 
-        if !is_cors_endpoint {
-            return Ok(res);
+    // Returns the `ENDPOINTS` entry matching `path`, preferring the most
+    // specific match (fewest wildcard segments) when more than one
+    // pattern matches, e.g. `permissions/_/*` over `permissions/*/*` for
+    // `/permissions/_/taxon`.
+    fn match_path(path: &[String]) -> Option<&'static Endpoint> {
+        CORS::ENDPOINTS.iter()
+            .filter(|endpoint| {
+                endpoint.path.len() == path.len() &&
+                endpoint.path.iter().enumerate().all(|(i, segment)| {
+                    path[i] == *segment || *segment == "*"
+                })
+            })
+            .max_by_key(|endpoint| {
+                endpoint.path.iter().filter(|segment| **segment != "*").count()
+            })
+    }
+
+    // Sets `Access-Control-Allow-Origin`, `Vary: Origin` and, if `endpoint`
+    // permits it, `Access-Control-Allow-Credentials` on `res`. Shared by the
+    // preflight and actual-response paths.
+    fn set_origin_headers(origin: String, endpoint: &Endpoint, res: &mut Response) {
+        res.headers.set(headers::AccessControlAllowOrigin::Value(origin));
+        res.headers.set(headers::Vary(vec![UniCase("origin".to_string())]));
+        if endpoint.policy.allow_credentials {
+            res.headers.set(headers::AccessControlAllowCredentials);
         }
+    }
+}
 
-        res.headers.set(headers::AccessControlAllowOrigin::Any);
+impl BeforeMiddleware for CORS {
+    // Handles CORS preflight `OPTIONS` requests. A request is a preflight
+    // request if it carries an `Access-Control-Request-Method` header;
+    // anything else is passed through to the router unchanged.
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        if req.method != Options {
+            return Ok(());
+        }
+
+        let requested_method = match
+            req.headers.get::<headers::AccessControlRequestMethod>() {
+            Some(&headers::AccessControlRequestMethod(ref method)) =>
+                method.clone(),
+            None => return Ok(()),
+        };
+
+        let endpoint = match CORS::match_path(&req.url.path) {
+            Some(endpoint) if endpoint.methods.contains(&requested_method) =>
+                endpoint,
+            _ => return Err(IronError::new(
+                    StringError("CORS preflight rejected".to_string()),
+                    status::Forbidden)),
+        };
+
+        // As in `after`, a request whose origin isn't permitted gets no
+        // CORS headers at all rather than a partially-filled-in response.
+        let origin = match
+            self.allow_origin(req.headers.get::<headers::Origin>()) {
+            Some(origin) => origin,
+            None => return Err(IronError::new(
+                    StringError("CORS preflight rejected".to_string()),
+                    status::Forbidden)),
+        };
+
+        let mut res = Response::with(status::NoContent);
+        CORS::set_origin_headers(origin, endpoint, &mut res);
+
+        res.headers.set(headers::AccessControlAllowMethods(
+                endpoint.methods.to_vec()));
+        if let Some(requested_headers) =
+            req.headers.get::<headers::AccessControlRequestHeaders>() {
+            res.headers.set(headers::AccessControlAllowHeaders(
+                    requested_headers.0.clone()));
+        }
+        if let Some(max_age) = self.max_age {
+            res.headers.set(headers::AccessControlMaxAge(max_age));
+        }
+
+        Err(IronError {
+            error: Box::new(StringError("CORS preflight".to_string())),
+            response: res,
+        })
+    }
+}
+
+impl AfterMiddleware for CORS {
+    fn after(&self, req: &mut Request, mut res: Response)
+        -> IronResult<Response> {
+
+        let endpoint = match CORS::match_path(&req.url.path) {
+            Some(endpoint) if endpoint.methods.contains(&req.method) =>
+                endpoint,
+            _ => return Ok(res),
+        };
+
+        let origin = self.allow_origin(req.headers.get::<headers::Origin>());
+        let origin = match origin {
+            Some(origin) => origin,
+            None => return Ok(res),
+        };
+
+        CORS::set_origin_headers(origin, endpoint, &mut res);
         res.headers.set(headers::AccessControlAllowHeaders(
-                vec![UniCase("accept".to_string()),
-                UniCase("content-type".to_string())]));
+                endpoint.policy.allowed_headers.iter()
+                    .map(|h| UniCase(h.to_string()))
+                    .collect()));
         res.headers.set(headers::AccessControlAllowMethods(
-                vec![Get,Head,Post,Delete,Options,Put,Patch]));
+                endpoint.methods.to_vec()));
         Ok(res)
     }
 }
@@ -196,7 +400,9 @@ impl UsersRouter {
         router.put("/permissions/:user/:taxon", UsersRouter::not_implemented);
 
         let mut chain = Chain::new(router);
-        chain.link_after(CORS);
+        let cors = CorsBuilder::new().build();
+        chain.link_before(cors.clone());
+        chain.link_after(cors);
 
         chain
     }
@@ -207,26 +413,30 @@ fn test_cors_allowed_endpoints() {
     use self::iron::method;
     use super::stubs::*;
 
-    // Test that all CORS allowed endpoints get the appropriate CORS headers.
+    // Test that all CORS allowed endpoints get the appropriate CORS headers
+    // for a request carrying an Origin header.
     for endpoint in CORS::ENDPOINTS {
-        let (ref method, path) = *endpoint;
-        let path = path.join("/").replace("*", "foo");
-        let mut req = request(method, &path);
-        match CORS.after(&mut req, Response::new()) {
-            Ok(res) => {
-                let headers = &res.headers;
-                assert!(headers.has::<headers::AccessControlAllowOrigin>());
-                assert!(headers.has::<headers::AccessControlAllowHeaders>());
-                assert!(headers.has::<headers::AccessControlAllowMethods>());
-            },
-            _ => assert!(false)
+        let path = endpoint.path.join("/").replace("*", "foo");
+        for method in endpoint.methods {
+            let mut req = request(method, &path);
+            req.headers.set(headers::Origin::new("http", "example.com", None));
+            match CORS::new().after(&mut req, Response::new()) {
+                Ok(res) => {
+                    let headers = &res.headers;
+                    assert!(headers.has::<headers::AccessControlAllowOrigin>());
+                    assert!(headers.has::<headers::AccessControlAllowHeaders>());
+                    assert!(headers.has::<headers::AccessControlAllowMethods>());
+                },
+                _ => assert!(false)
+            }
         }
     }
 
     // Test that non-CORS-allowed endpoints like POST /setup don't get CORS
     // headers in the response.
     let mut req = request(&method::Post, "/setup");
-    match CORS.after(&mut req, Response::new()) {
+    req.headers.set(headers::Origin::new("http", "example.com", None));
+    match CORS::new().after(&mut req, Response::new()) {
         Ok(res) => {
             let headers = &res.headers;
             assert!(!headers.has::<headers::AccessControlAllowOrigin>());
@@ -237,6 +447,174 @@ fn test_cors_allowed_endpoints() {
     }
 }
 
+#[test]
+fn test_cors_preflight_allowed_endpoints() {
+    use super::stubs::*;
+
+    // Test that a preflight request for an allowed endpoint/method short-
+    // circuits with a 204 and the appropriate CORS headers.
+    for endpoint in CORS::ENDPOINTS {
+        let path = endpoint.path.join("/").replace("*", "foo");
+        for method in endpoint.methods {
+            let mut req = request(&Options, &path);
+            req.headers.set(headers::Origin::new("http", "example.com", None));
+            req.headers.set(headers::AccessControlRequestMethod(method.clone()));
+            match CORS::new().before(&mut req) {
+                Err(IronError { response: res, .. }) => {
+                    assert_eq!(res.status.unwrap(), status::NoContent);
+                    let headers = &res.headers;
+                    assert!(headers.has::<headers::AccessControlAllowOrigin>());
+                    assert!(headers.has::<headers::AccessControlAllowMethods>());
+                },
+                _ => assert!(false)
+            }
+        }
+    }
+}
+
+#[test]
+fn test_cors_preflight_rejects_disallowed_method() {
+    use super::stubs::*;
+
+    // Test that a preflight request for a method that isn't registered for
+    // the path is rejected with a 403.
+    let mut req = request(&Options, "/users");
+    req.headers.set(headers::Origin::new("http", "example.com", None));
+    req.headers.set(headers::AccessControlRequestMethod(Put));
+    match CORS::new().before(&mut req) {
+        Err(IronError { response: res, .. }) => {
+            assert_eq!(res.status.unwrap(), status::Forbidden);
+        },
+        _ => assert!(false)
+    }
+}
+
+#[test]
+fn test_cors_preflight_rejects_disallowed_origin() {
+    use super::stubs::*;
+
+    // Test that a preflight request for an allowed path/method but a
+    // disallowed origin is rejected with no CORS headers leaked, rather
+    // than only dropping Access-Control-Allow-Origin/Vary while still
+    // advertising Access-Control-Allow-Methods et al.
+    let cors = CorsBuilder::new()
+        .allowed_origins(AllowedOrigins::List(HashSet::new()))
+        .build();
+    let mut req = request(&Options, "/users");
+    req.headers.set(headers::Origin::new("http", "example.com", None));
+    req.headers.set(headers::AccessControlRequestMethod(Get));
+    match cors.before(&mut req) {
+        Err(IronError { response: res, .. }) => {
+            assert_eq!(res.status.unwrap(), status::Forbidden);
+            let headers = &res.headers;
+            assert!(!headers.has::<headers::AccessControlAllowOrigin>());
+            assert!(!headers.has::<headers::AccessControlAllowMethods>());
+            assert!(!headers.has::<headers::AccessControlAllowHeaders>());
+        },
+        _ => assert!(false)
+    }
+}
+
+#[test]
+fn test_cors_builder_max_age() {
+    use super::stubs::*;
+
+    // Test that a configured max age is emitted on preflight responses,
+    // and that it's absent when the builder default is used.
+    let cors = CorsBuilder::new().max_age(600).build();
+    let mut req = request(&Options, "/users");
+    req.headers.set(headers::Origin::new("http", "example.com", None));
+    req.headers.set(headers::AccessControlRequestMethod(Get));
+    match cors.before(&mut req) {
+        Err(IronError { response: res, .. }) => {
+            assert_eq!(res.headers.get::<headers::AccessControlMaxAge>(),
+                       Some(&headers::AccessControlMaxAge(600)));
+        },
+        _ => assert!(false)
+    }
+
+    let mut req = request(&Options, "/users");
+    req.headers.set(headers::Origin::new("http", "example.com", None));
+    req.headers.set(headers::AccessControlRequestMethod(Get));
+    match CORS::new().before(&mut req) {
+        Err(IronError { response: res, .. }) => {
+            assert!(!res.headers.has::<headers::AccessControlMaxAge>());
+        },
+        _ => assert!(false)
+    }
+}
+
+#[test]
+fn test_cors_matches_most_specific_path() {
+    use super::stubs::*;
+
+    // `/permissions/_/taxon` matches both the `permissions/*/*` pattern
+    // (DEFAULT policy, and PUT among its methods) and the more specific
+    // `permissions/_/*` pattern (READ_ONLY policy, GET only). The latter
+    // is the one the router actually registers PUT-less, so it must win.
+    let mut req = request(&Get, "/permissions/_/taxon");
+    req.headers.set(headers::Origin::new("http", "example.com", None));
+    match CORS::new().after(&mut req, Response::new()) {
+        Ok(res) => {
+            assert_eq!(res.headers.get::<headers::AccessControlAllowHeaders>(),
+                       Some(&headers::AccessControlAllowHeaders(
+                               vec![UniCase("accept".to_string())])));
+            let methods = &res.headers
+                .get::<headers::AccessControlAllowMethods>().unwrap().0;
+            assert!(!methods.contains(&Put));
+        },
+        _ => assert!(false)
+    }
+
+    let mut req = request(&Options, "/permissions/_/taxon");
+    req.headers.set(headers::Origin::new("http", "example.com", None));
+    req.headers.set(headers::AccessControlRequestMethod(Put));
+    match CORS::new().before(&mut req) {
+        Err(IronError { response: res, .. }) => {
+            assert_eq!(res.status.unwrap(), status::Forbidden);
+        },
+        _ => assert!(false)
+    }
+}
+
+#[test]
+fn test_cors_set_origin_headers_allow_credentials() {
+    // Test that Access-Control-Allow-Credentials is emitted exactly when
+    // the matched endpoint's policy permits it.
+    const CREDENTIALED: Endpoint = Endpoint {
+        path: &["users", "*"],
+        methods: &[Put],
+        policy: EndpointPolicy { allowed_headers: &["accept"],
+                                  allow_credentials: true },
+    };
+
+    let mut res = Response::new();
+    CORS::set_origin_headers("http://example.com".to_string(), &CREDENTIALED,
+                              &mut res);
+    assert!(res.headers.has::<headers::AccessControlAllowCredentials>());
+    assert_eq!(res.headers.get::<headers::AccessControlAllowOrigin>(),
+               Some(&headers::AccessControlAllowOrigin::Value(
+                       "http://example.com".to_string())));
+
+    let mut res = Response::new();
+    CORS::set_origin_headers("http://example.com".to_string(),
+                              &CORS::ENDPOINTS[0], &mut res);
+    assert!(!res.headers.has::<headers::AccessControlAllowCredentials>());
+}
+
+#[test]
+#[should_panic]
+fn test_cors_builder_rejects_credentials_with_any_origin() {
+    const CREDENTIALED: &'static [Endpoint] = &[Endpoint {
+        path: &["users", "*"],
+        methods: &[Put],
+        policy: EndpointPolicy { allowed_headers: &["accept"],
+                                  allow_credentials: true },
+    }];
+    CorsBuilder::check_credentialed_endpoints(&AllowedOrigins::Any,
+                                               CREDENTIALED);
+}
+
 #[test]
 fn test_users_router_not_implemented_endpoints() {
     use self::iron::middleware::Handler;
@@ -245,7 +623,7 @@ fn test_users_router_not_implemented_endpoints() {
 
     let router = UsersRouter::new();
 
-    const ENDPOINTS: &'static[Endpoint] = &[
+    const ENDPOINTS: &'static[(Method, &'static[&'static str])] = &[
         (Method::Post,      &["invitations"]),
         (Method::Get,       &["invitations"]),
         (Method::Delete,    &["invitations"]),